@@ -1,24 +1,47 @@
 use std;
 use std::fmt;
-use std::mem::ManuallyDrop;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
 
 #[cfg(feature = "serde")]
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-// Little helper function to turn (bool, T) into Option<T>.
-fn to_option<T>(b: bool, some: T) -> Option<T> {
-    match b {
-        true => Some(some),
-        false => None,
-    }
-}
+// Number of high bits of `version` reserved for an opaque, user-set tag
+// rather than occupancy/generation. Must be in 0..=30; the remaining bits
+// carry the two lifecycle state bits (see below) and the generation
+// counter. Raising this
+// shrinks the generation space, making version wraparound happen sooner.
+const RESERVED_BITS: u32 = 8;
+const VERSION_BITS: u32 = 32 - RESERVED_BITS;
+const VERSION_MASK: u32 = ((1u64 << VERSION_BITS) - 1) as u32;
+const TAG_MASK: u32 = !VERSION_MASK;
+
+// The two lowest version bits encode the slot's lifecycle state; the rest
+// of the version bits are the generation counter. OCCUPIED_BIT alone means
+// normally occupied; OCCUPIED_BIT|LEASED_BIT means its value has been
+// temporarily taken out via `lease` but the index is still reserved;
+// LEASED_BIT alone means the slot has been permanently retired because its
+// generation counter was exhausted.
+const OCCUPIED_BIT: u32 = 0b01;
+const LEASED_BIT: u32 = 0b10;
+const STATE_BITS: u32 = 2;
+const STATE_MASK: u32 = OCCUPIED_BIT | LEASED_BIT;
+const LEASED: u32 = OCCUPIED_BIT | LEASED_BIT;
+const RETIRED: u32 = LEASED_BIT;
+
+// The highest generation a slot may reach before being retired rather than
+// recycled, to guarantee a key can never be reissued after wraparound
+// (a classic ABA hazard). Defaults to the full range the generation
+// counter can hold; lower this to retire slots more eagerly.
+const MAX_GENERATION: u32 = VERSION_MASK >> STATE_BITS;
 
 
 // A slot, which represents storage for a value and a current version.
 // Can be occupied or vacant
 pub struct Slot<T> {
-    // A value when occupied, uninitialized memory otherwise.
-    value: ManuallyDrop<T>,
+    // A value when occupied, uninitialized memory otherwise. Every read of
+    // this field must be gated on occupied() returning true.
+    value: MaybeUninit<T>,
 
     // Even = vacant, odd = occupied.
     version: u32,
@@ -31,68 +54,176 @@ pub struct Slot<T> {
 impl<T> Slot<T> {
     pub fn new() -> Self {
         Self {
-            value: unsafe { std::mem::uninitialized() },
+            value: MaybeUninit::uninit(),
             version: 0,
             next_free: 0,
         }
     }
 
-    // Is this slot occupied?
+    // The occupancy/generation bits, with any tag bits masked out.
+    fn version_bits(&self) -> u32 {
+        self.version & VERSION_MASK
+    }
+
+    // Is this slot occupied? Returns false for a leased slot: its value has
+    // been taken out, even though the index is still reserved.
     pub fn occupied(&self) -> bool {
-        self.version % 2 > 0
+        self.version_bits() & STATE_MASK == OCCUPIED_BIT
+    }
+
+    // Is this slot's value currently leased out (see `lease`/`restore`)?
+    pub fn is_leased(&self) -> bool {
+        self.version_bits() & STATE_MASK == LEASED
     }
 
     // Get an OccupiedVersion for this slot. If the slot is currently unoccupied
-    // it will return the version it would have when it gets occupied.
+    // or leased it will return the version it has (or would have) while
+    // normally occupied. Tag bits are never part of this value.
     pub fn occupied_version(&self) -> u32 {
-        self.version | 1
+        (self.version_bits() & !LEASED_BIT) | OCCUPIED_BIT
     }
 
-    // Checks the slot's version for equality. If this returns true you also
-    // know the slot is occupied.
+    // Checks the slot's version for equality, ignoring tag bits. If this
+    // returns true you also know the slot is occupied.
     pub fn has_version(&self, version: u32) -> bool {
-        self.version == version
+        self.version_bits() == (version & VERSION_MASK)
+    }
+
+    // Get the opaque tag stashed in this slot's reserved bits. Survives
+    // store_value/remove_value and is unrelated to occupancy or version.
+    pub fn tag(&self) -> u32 {
+        (self.version & TAG_MASK).checked_shr(VERSION_BITS).unwrap_or(0)
+    }
+
+    // Set the opaque tag stashed in this slot's reserved bits. Only the low
+    // RESERVED_BITS bits of `tag` are kept.
+    pub fn set_tag(&mut self, tag: u32) {
+        self.version =
+            self.version_bits() | (tag.checked_shl(VERSION_BITS).unwrap_or(0) & TAG_MASK);
     }
 
     // Get the slot's value, if occupied.
     pub fn value(&self) -> Option<&T> {
-        to_option(self.occupied(), &self.value)
+        if self.occupied() {
+            Some(unsafe { &*self.value.as_ptr() })
+        } else {
+            None
+        }
     }
 
     pub fn value_mut(&mut self) -> Option<&mut T> {
-        let occupied = self.occupied();
-        to_option(occupied, &mut self.value)
+        if self.occupied() {
+            Some(unsafe { &mut *self.value.as_mut_ptr() })
+        } else {
+            None
+        }
     }
 
     // Get the slot's value, if occupied and the correct version is given.
     pub fn get_versioned(&self, version: u32) -> Option<&T> {
-        let correct_version = self.has_version(version);
-        to_option(correct_version, &self.value)
+        if self.has_version(version) {
+            Some(unsafe { &*self.value.as_ptr() })
+        } else {
+            None
+        }
     }
 
     pub fn get_versioned_mut(&mut self, version: u32) -> Option<&mut T> {
-        let correct_version = self.has_version(version);
-        to_option(correct_version, &mut self.value)
+        if self.has_version(version) {
+            Some(unsafe { &mut *self.value.as_mut_ptr() })
+        } else {
+            None
+        }
     }
 
     // Get the slot's value without any safety checks.
     pub unsafe fn get_unchecked(&self) -> &T {
-        &self.value
+        &*self.value.as_ptr()
     }
     pub unsafe fn get_unchecked_mut(&mut self) -> &mut T {
-        &mut self.value
+        &mut *self.value.as_mut_ptr()
     }
 
     // Store a new value. Must be unoccupied before storing.
     pub unsafe fn store_value(&mut self, value: T) {
+        self.value.as_mut_ptr().write(value);
         self.version |= 1;
-        self.value = ManuallyDrop::new(value);
     }
 
-    // Remove a stored value. Must be occupied before removing.
+    // Construct a value directly into this slot's storage instead of moving
+    // in an already-built `T`. Must be unoccupied before calling. `init` is
+    // handed a pointer to uninitialized storage and must fully initialize
+    // it before returning; this is what lets callers store `!Unpin` values
+    // (self-referential structs, generator-based futures) without ever
+    // moving them after construction.
+    pub unsafe fn emplace_with<F: FnOnce(*mut T)>(&mut self, init: F) {
+        init(self.value.as_mut_ptr());
+        self.version |= 1;
+    }
+
+    // Get a pinned mutable reference to the slot's value, if occupied and
+    // the correct version is given. A slot never moves its own backing
+    // storage while occupied, but that's not enough on its own: the caller
+    // must also guarantee the `Slot` itself is not moved (e.g. it lives
+    // behind a `Box`, or in storage that never relocates occupied slots)
+    // for as long as any value built up through the returned `Pin` relies
+    // on not being moved. That guarantee can't be checked here, which is
+    // why this is `unsafe`.
+    pub unsafe fn get_pinned_mut(&mut self, version: u32) -> Option<Pin<&mut T>> {
+        if self.has_version(version) {
+            Some(Pin::new_unchecked(&mut *self.value.as_mut_ptr()))
+        } else {
+            None
+        }
+    }
+
+    // Is this slot permanently retired? A retired slot's generation counter
+    // was exhausted, so its index must never be handed out again: reusing
+    // it could let a stale key alias whatever value is stored there next.
+    //
+    // No count of retired slots is kept: a Slot knows only its own state,
+    // and this crate (slot.rs and atomic_slot.rs) has no collection type
+    // that owns many slots. A slot only becomes retired inside
+    // `remove_value`, so `is_retired()` right after that call tells whether
+    // that call retired it.
+    pub fn is_retired(&self) -> bool {
+        self.version_bits() & STATE_MASK == RETIRED
+    }
+
+    // Remove a stored value. Must be occupied before removing. If the
+    // generation counter is already at MAX_GENERATION, bumping it further
+    // would wrap back to a value some live key may still hold, so the slot
+    // is retired instead: its index must not be pushed onto the free list.
     pub unsafe fn remove_value(&mut self) -> T {
-        self.version = self.version.wrapping_add(1);
-        std::mem::replace(&mut *self.value, std::mem::uninitialized())
+        let value = self.value.as_ptr().read();
+        let generation = self.version_bits() >> STATE_BITS;
+        let next_version = if generation >= MAX_GENERATION {
+            RETIRED
+        } else {
+            (generation + 1) << STATE_BITS
+        };
+        self.version = (self.version & TAG_MASK) | next_version;
+        value
+    }
+
+    // Take the value out of an occupied slot, leaving it "leased": the
+    // index stays reserved (it is not pushed onto the free list and the
+    // generation is unchanged) but `value`/`get_versioned`/`occupied` all
+    // report it as absent, so stale access is rejected. Must be occupied
+    // before leasing. Pair with `restore` to give the value back under the
+    // same key, which a plain remove+insert cannot do since that mints a
+    // new version.
+    pub unsafe fn lease(&mut self) -> T {
+        let value = self.value.as_ptr().read();
+        self.version |= LEASED_BIT;
+        value
+    }
+
+    // Put a value back into a leased slot, re-marking it occupied with its
+    // original version. Must be leased before restoring.
+    pub unsafe fn restore(&mut self, value: T) {
+        self.value.as_mut_ptr().write(value);
+        self.version &= !LEASED_BIT;
     }
 }
 
@@ -100,7 +231,7 @@ impl<T> Drop for Slot<T> {
     fn drop(&mut self) {
         if self.occupied() {
             unsafe {
-                ManuallyDrop::drop(&mut self.value);
+                std::ptr::drop_in_place(self.value.as_mut_ptr());
             }
         }
     }
@@ -113,9 +244,9 @@ where
     fn clone(&self) -> Self {
         Slot::<T> {
             value: if self.occupied() {
-                self.value.clone()
+                MaybeUninit::new(unsafe { &*self.value.as_ptr() }.clone())
             } else {
-                unsafe { std::mem::uninitialized() }
+                MaybeUninit::uninit()
             },
             version: self.version,
             next_free: self.next_free,
@@ -131,7 +262,15 @@ where
         let mut builder = fmt.debug_struct("Slot");
         builder.field("version", &self.version);
         if self.occupied() {
-            builder.field("value", &self.value).finish()
+            builder.field("value", &self.value()).finish()
+        } else if self.is_leased() {
+            // Its value has been taken out and the index is reserved, not
+            // on the free list, so next_free would be stale here.
+            builder.field("leased", &true).finish()
+        } else if self.is_retired() {
+            // Permanently vacant and never pushed onto the free list, so
+            // next_free would be stale here too.
+            builder.field("retired", &true).finish()
         } else {
             builder.field("next_free", &self.next_free).finish()
         }
@@ -151,8 +290,8 @@ impl<'a, T> From<SafeSlot<T>> for Slot<T> {
     fn from(safe_slot: SafeSlot<T>) -> Self {
         Slot {
             value: match safe_slot.value {
-                Some(value) => ManuallyDrop::new(value),
-                None => unsafe { std::mem::uninitialized() },
+                Some(value) => MaybeUninit::new(value),
+                None => MaybeUninit::uninit(),
             },
             version: safe_slot.version,
             next_free: 0,
@@ -193,7 +332,10 @@ where
         D: Deserializer<'de>,
     {
         let safe_slot: SafeSlot<T> = Deserialize::deserialize(deserializer)?;
-        let occupied = safe_slot.version % 2 > 0;
+        // Derive "should have a value" from the actual state bits, not raw
+        // parity: a Leased slot is also odd (OCCUPIED_BIT|LEASED_BIT) but
+        // correctly has no value.
+        let occupied = (safe_slot.version & VERSION_MASK) & STATE_MASK == OCCUPIED_BIT;
         if occupied ^ safe_slot.value.is_some() {
             return Err(de::Error::custom(&"inconsistent occupation in Slot"));
         }
@@ -204,22 +346,234 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[cfg(feature = "serde")]
     use serde_json;
 
+    // Run under `cargo +nightly miri test` to check for UB across
+    // insert/remove/drop cycles.
+    #[test]
+    fn insert_remove_cycle() {
+        let mut slot: Slot<String> = Slot::new();
+        assert!(!slot.occupied());
+
+        unsafe {
+            slot.store_value("hello".to_owned());
+        }
+        assert!(slot.occupied());
+        assert_eq!(slot.value(), Some(&"hello".to_owned()));
+
+        let removed = unsafe { slot.remove_value() };
+        assert_eq!(removed, "hello");
+        assert!(!slot.occupied());
+        assert_eq!(slot.value(), None);
+
+        unsafe {
+            slot.store_value("world".to_owned());
+        }
+        assert_eq!(slot.value(), Some(&"world".to_owned()));
+    }
+
+    #[test]
+    fn drop_only_runs_when_occupied() {
+        use std::rc::Rc;
+
+        let rc = Rc::new(());
+
+        // A vacant slot holds no value, so dropping it must not touch the
+        // uninitialized storage.
+        let slot: Slot<Rc<()>> = Slot::new();
+        drop(slot);
+
+        // An occupied slot drops its value exactly once.
+        let mut slot: Slot<Rc<()>> = Slot::new();
+        unsafe {
+            slot.store_value(rc.clone());
+        }
+        drop(slot);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn clone_leaves_vacant_slots_uninitialized() {
+        let slot: Slot<String> = Slot::new();
+        let cloned = slot.clone();
+        assert!(!cloned.occupied());
+
+        let mut slot: Slot<String> = Slot::new();
+        unsafe {
+            slot.store_value("hi".to_owned());
+        }
+        let cloned = slot.clone();
+        assert_eq!(cloned.value(), Some(&"hi".to_owned()));
+    }
+
     #[cfg(feature = "serde")]
     #[test]
     fn slot_serde() {
-        let slot = Slot {
-            value: ManuallyDrop::new("test"),
-            version: 1,
-            next_free: 42,
-        };
+        let mut slot: Slot<&str> = Slot::new();
+        unsafe {
+            slot.store_value("test");
+        }
+        slot.next_free = 42;
 
         let ser = serde_json::to_string(&slot).unwrap();
         let de: Slot<&str> = serde_json::from_str(&ser).unwrap();
-        assert_eq!(de.value, slot.value);
+        assert_eq!(de.value(), slot.value());
         assert_eq!(de.version, slot.version);
         assert_eq!(de.next_free, 0); // next_free should not survive serialization.
     }
+
+    #[test]
+    fn tag_is_independent_of_occupancy_and_version() {
+        let mut slot: Slot<&str> = Slot::new();
+        slot.set_tag(0xab);
+        assert_eq!(slot.tag(), 0xab);
+        assert!(!slot.occupied());
+
+        unsafe {
+            slot.store_value("tagged");
+        }
+        assert_eq!(slot.tag(), 0xab);
+        assert!(slot.occupied());
+        let version = slot.occupied_version();
+
+        unsafe {
+            slot.remove_value();
+        }
+        assert_eq!(slot.tag(), 0xab);
+        assert!(!slot.occupied());
+
+        slot.set_tag(0xcd);
+        assert_eq!(slot.tag(), 0xcd);
+        assert!(!slot.has_version(version));
+    }
+
+    #[test]
+    fn emplace_with_constructs_in_place() {
+        let mut slot: Slot<(u32, u32)> = Slot::new();
+        unsafe {
+            slot.emplace_with(|ptr| {
+                ptr.write((1, 2));
+            });
+        }
+        assert_eq!(slot.value(), Some(&(1, 2)));
+    }
+
+    #[test]
+    fn get_pinned_mut_respects_version() {
+        let mut slot: Slot<String> = Slot::new();
+        unsafe {
+            slot.store_value("pinned".to_owned());
+        }
+        let version = slot.occupied_version();
+
+        assert!(unsafe { slot.get_pinned_mut(version + 2) }.is_none());
+
+        let pinned = unsafe { slot.get_pinned_mut(version) }.unwrap();
+        assert_eq!(&*pinned, "pinned");
+    }
+
+    #[test]
+    fn lease_and_restore_keep_the_same_key() {
+        let mut slot: Slot<String> = Slot::new();
+        unsafe {
+            slot.store_value("owned".to_owned());
+        }
+        let version = slot.occupied_version();
+
+        let leased = unsafe { slot.lease() };
+        assert_eq!(leased, "owned");
+        assert!(!slot.occupied());
+        assert!(slot.is_leased());
+        assert_eq!(slot.value(), None);
+        assert!(!slot.has_version(version));
+
+        unsafe {
+            slot.restore(leased);
+        }
+        assert!(slot.occupied());
+        assert!(!slot.is_leased());
+        assert_eq!(slot.value(), Some(&"owned".to_owned()));
+        assert!(slot.has_version(version));
+        assert_eq!(slot.occupied_version(), version);
+    }
+
+    #[test]
+    fn debug_distinguishes_all_four_lifecycle_states() {
+        let mut slot: Slot<&str> = Slot::new();
+        assert!(format!("{:?}", slot).contains("next_free"));
+
+        unsafe {
+            slot.store_value("hi");
+        }
+        assert!(format!("{:?}", slot).contains("value"));
+
+        let leased = unsafe { slot.lease() };
+        assert!(format!("{:?}", slot).contains("leased"));
+        unsafe {
+            slot.restore(leased);
+        }
+
+        slot.version = (MAX_GENERATION << STATE_BITS) | OCCUPIED_BIT;
+        unsafe {
+            slot.remove_value();
+        }
+        assert!(format!("{:?}", slot).contains("retired"));
+    }
+
+    #[test]
+    fn remove_retires_slot_on_generation_exhaustion() {
+        let mut slot: Slot<u32> = Slot::new();
+        unsafe {
+            slot.store_value(1);
+        }
+        // Force the generation counter right up to its ceiling without
+        // looping MAX_GENERATION times.
+        slot.version = (MAX_GENERATION << STATE_BITS) | OCCUPIED_BIT;
+        assert!(!slot.is_retired());
+
+        unsafe {
+            slot.remove_value();
+        }
+        assert!(slot.is_retired());
+        assert!(!slot.occupied());
+        assert!(!slot.is_leased());
+
+        // A retired slot's index is permanently unusable; nothing ever
+        // clears is_retired() again.
+        assert!(slot.is_retired());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn slot_serde_preserves_tag() {
+        let mut slot: Slot<&str> = Slot::new();
+        slot.set_tag(7);
+        unsafe {
+            slot.store_value("test");
+        }
+
+        let ser = serde_json::to_string(&slot).unwrap();
+        let de: Slot<&str> = serde_json::from_str(&ser).unwrap();
+        assert_eq!(de.tag(), 7);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn slot_serde_round_trips_a_leased_slot() {
+        let mut slot: Slot<&str> = Slot::new();
+        unsafe {
+            slot.store_value("test");
+        }
+        unsafe {
+            slot.lease();
+        }
+
+        let ser = serde_json::to_string(&slot).unwrap();
+        let de: Slot<&str> = serde_json::from_str(&ser).unwrap();
+        assert_eq!(de.value(), None);
+        assert!(!de.occupied());
+    }
 }